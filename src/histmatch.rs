@@ -1,4 +1,331 @@
-use std::{collections::BTreeMap, ops::Add};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Add,
+    path::Path,
+};
+
+use image::{DynamicImage, RgbImage};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// Where an image to be histogram-matched comes from.
+///
+/// This lets callers hand over a file path or an encoded byte buffer instead
+/// of having to decode the image themselves and slice out a raw `&mut [u8]`.
+pub enum ImageSource<'a> {
+    /// Load and decode an image from disk.
+    Path(&'a Path),
+    /// Decode an image from an in-memory encoded buffer (png, jpeg, ...).
+    Bytes(&'a [u8]),
+    /// An image that has already been decoded.
+    Decoded(DynamicImage),
+}
+
+impl<'a> ImageSource<'a> {
+    /// Decodes the source and converts it to 8-bit RGB, regardless of the
+    /// original pixel format (RGBA, grayscale, ...).
+    fn into_rgb(self) -> image::ImageResult<RgbImage> {
+        let decoded = match self {
+            ImageSource::Path(path) => image::open(path)?,
+            ImageSource::Bytes(bytes) => image::load_from_memory(bytes)?,
+            ImageSource::Decoded(image) => image,
+        };
+        Ok(decoded.to_rgb8())
+    }
+}
+
+/// Options controlling how [`match_histogram`] matches and writes back
+/// pixels.
+pub struct MatchOptions {
+    pub mode: MatchMode,
+    /// When `true`, diffuse the LUT's quantization error to neighboring
+    /// pixels (Floyd-Steinberg) instead of writing `map[value]` directly.
+    /// Removes banding when the mapping squeezes many source levels onto
+    /// few reference levels, at the cost of a slower, non-parallel apply.
+    /// Only applies to `MatchMode::Rgb`.
+    pub dither: bool,
+    /// When present, restricts matching to source pixels where the mask is
+    /// `true`, one entry per pixel in row-major order. Only applies to
+    /// `MatchMode::Rgb`.
+    pub mask: Option<Vec<bool>>,
+    /// When present, runs [`weighted_median_filter`] on the matched result,
+    /// guided by the pre-match source image, to remove LUT banding while
+    /// preserving edges.
+    pub post_filter: Option<PostFilterOptions>,
+}
+
+impl Default for MatchOptions {
+    fn default() -> Self {
+        Self {
+            mode: MatchMode::Rgb,
+            dither: false,
+            mask: None,
+            post_filter: None,
+        }
+    }
+}
+
+/// Parameters for the [`weighted_median_filter`] post-pass; see
+/// [`MatchOptions::post_filter`].
+pub struct PostFilterOptions {
+    /// Window half-width: the filter considers a `(2 * radius + 1)`-wide
+    /// square of neighbors around each pixel.
+    pub radius: i32,
+    /// Gaussian falloff for the guide-image color-distance weighting; larger
+    /// values smooth across bigger color differences.
+    pub sigma: f32,
+}
+
+/// Which channels to match the histogram over.
+pub enum MatchMode {
+    /// Match the R, G and B channels independently, as
+    /// [`match_histogram_rgb_array`] does.
+    Rgb,
+    /// Convert to CIELAB and match only the L (luminance) channel, leaving
+    /// a/b untouched. Avoids the hue shifts independent RGB matching causes.
+    Luminance,
+    /// Convert to CIELAB and match all three L, a, b channels.
+    Lab,
+}
+
+/// Decodes `source` and `reference`, matches `source`'s histogram to
+/// `reference`'s according to `mode` and returns the result.
+///
+/// This is a convenience wrapper around [`match_histogram_rgb_array`] for
+/// callers who don't already have a decoded, interleaved RGB buffer on hand.
+///
+/// 16-bit/HDR input ([`match_histogram_rgb_array_16`]) isn't wired in here:
+/// `ImageSource::into_rgb` normalizes everything to 8-bit `RgbImage` by
+/// design, so HDR callers should use the `ImageChannels16` entry point
+/// directly on their own decoded buffer instead.
+///
+/// `options.dither` and `options.mask` only apply to `MatchMode::Rgb`;
+/// `MatchMode::Luminance`/`MatchMode::Lab` always apply the hard LUT over
+/// the whole frame.
+pub fn match_histogram(
+    source: ImageSource,
+    reference: ImageSource,
+    options: MatchOptions,
+) -> image::ImageResult<RgbImage> {
+    let mut source = source.into_rgb()?;
+    let reference = reference.into_rgb()?;
+    let guide = options.post_filter.is_some().then(|| source.clone());
+
+    match options.mode {
+        MatchMode::Rgb => {
+            let (width, height) = (source.width(), source.height());
+            let (ref_width, ref_height) = (reference.width(), reference.height());
+            let mut reference = reference.into_raw();
+
+            let src_channels = ImageChannels::new(&mut source, width, height);
+            let ref_channels = ImageChannels::new(&mut reference, ref_width, ref_height);
+            let mask = options.mask.as_deref();
+            match_histogram_rgb_array_full(src_channels, ref_channels, options.dither, mask);
+        }
+        MatchMode::Luminance => match_histogram_lab(&mut source, &reference, false),
+        MatchMode::Lab => match_histogram_lab(&mut source, &reference, true),
+    }
+
+    if let Some(post_filter) = options.post_filter {
+        let guide = guide.expect("post_filter is Some, so guide was captured above");
+        let (width, height) = (source.width(), source.height());
+        let mut matched = source.into_raw();
+        let mut guide = guide.into_raw();
+
+        let matched_channels = ImageChannels::new(&mut matched, width, height);
+        let guide_channels = ImageChannels::new(&mut guide, width, height);
+        let filtered = weighted_median_filter(
+            &matched_channels,
+            &guide_channels,
+            post_filter.radius,
+            post_filter.sigma,
+        );
+
+        source = RgbImage::from_raw(width, height, filtered)
+            .expect("weighted_median_filter preserves the buffer's dimensions");
+    }
+
+    Ok(source)
+}
+
+// --- CIELAB conversion -------------------------------------------------
+//
+// sRGB -> linear -> XYZ (D65) -> Lab, and back. Used by `match_histogram_lab`
+// so histogram matching can operate on a perceptual, decorrelated space
+// instead of independent R/G/B channels.
+
+const WHITE_XN: f32 = 0.95047;
+const WHITE_YN: f32 = 1.0;
+const WHITE_ZN: f32 = 1.08883;
+
+const L_RANGE: (f32, f32) = (0.0, 100.0);
+const AB_RANGE: (f32, f32) = (-128.0, 127.0);
+
+#[inline]
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[inline]
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[inline]
+fn linear_rgb_to_xyz(r: f32, g: f32, b: f32) -> (f32, f32, f32) {
+    (
+        0.4124564 * r + 0.3575761 * g + 0.1804375 * b,
+        0.2126729 * r + 0.7151522 * g + 0.0721750 * b,
+        0.0193339 * r + 0.119192 * g + 0.9503041 * b,
+    )
+}
+
+#[inline]
+fn xyz_to_linear_rgb(x: f32, y: f32, z: f32) -> (f32, f32, f32) {
+    (
+        3.2404542 * x - 1.5371385 * y - 0.4985314 * z,
+        -0.969266 * x + 1.8760108 * y + 0.0415560 * z,
+        0.0556434 * x - 0.2040259 * y + 1.0572252 * z,
+    )
+}
+
+#[inline]
+fn lab_f(t: f32) -> f32 {
+    const DELTA: f32 = 6.0 / 29.0;
+    if t > DELTA * DELTA * DELTA {
+        t.cbrt()
+    } else {
+        t / (3.0 * DELTA * DELTA) + 4.0 / 29.0
+    }
+}
+
+#[inline]
+fn lab_f_inv(t: f32) -> f32 {
+    const DELTA: f32 = 6.0 / 29.0;
+    if t > DELTA {
+        t * t * t
+    } else {
+        3.0 * DELTA * DELTA * (t - 4.0 / 29.0)
+    }
+}
+
+fn xyz_to_lab(x: f32, y: f32, z: f32) -> (f32, f32, f32) {
+    let fx = lab_f(x / WHITE_XN);
+    let fy = lab_f(y / WHITE_YN);
+    let fz = lab_f(z / WHITE_ZN);
+    (116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+fn lab_to_xyz(l: f32, a: f32, b: f32) -> (f32, f32, f32) {
+    let fy = (l + 16.0) / 116.0;
+    let fx = fy + a / 500.0;
+    let fz = fy - b / 200.0;
+    (
+        WHITE_XN * lab_f_inv(fx),
+        WHITE_YN * lab_f_inv(fy),
+        WHITE_ZN * lab_f_inv(fz),
+    )
+}
+
+fn rgb_to_lab(r: u8, g: u8, b: u8) -> (f32, f32, f32) {
+    let r = srgb_to_linear(r as f32 / 255.0);
+    let g = srgb_to_linear(g as f32 / 255.0);
+    let b = srgb_to_linear(b as f32 / 255.0);
+    let (x, y, z) = linear_rgb_to_xyz(r, g, b);
+    xyz_to_lab(x, y, z)
+}
+
+fn lab_to_rgb(l: f32, a: f32, b: f32) -> [u8; 3] {
+    let (x, y, z) = lab_to_xyz(l, a, b);
+    let (r, g, b) = xyz_to_linear_rgb(x, y, z);
+    let to_byte = |c: f32| (linear_to_srgb(c.clamp(0.0, 1.0)) * 255.0).round().clamp(0.0, 255.0) as u8;
+    [to_byte(r), to_byte(g), to_byte(b)]
+}
+
+/// Number of quantization bins used for each Lab channel's histogram. Lab
+/// values are continuous, unlike 8-bit RGB, so they need to be bucketed
+/// before they can be fed through the same CDF/mapping machinery.
+const LAB_BINS: usize = 256;
+
+#[inline]
+fn lab_bin(value: f32, range: (f32, f32)) -> usize {
+    let t = ((value - range.0) / (range.1 - range.0)).clamp(0.0, 1.0);
+    ((t * (LAB_BINS - 1) as f32).round() as usize).min(LAB_BINS - 1)
+}
+
+#[inline]
+fn lab_unbin(bin: u8, range: (f32, f32)) -> f32 {
+    range.0 + (bin as f32 / (LAB_BINS - 1) as f32) * (range.1 - range.0)
+}
+
+fn lab_channel_histogram(plane: &[f32], range: (f32, f32)) -> [f32; LAB_BINS] {
+    let mut hist = [0.0; LAB_BINS];
+    for &v in plane {
+        hist[lab_bin(v, range)] += 1.0;
+    }
+    hist
+}
+
+/// Matches `src_plane`'s histogram to `ref_plane`'s in-place over
+/// `range`. Values are quantized into `LAB_BINS` bins, the bin-to-bin LUT
+/// from [`mapping`] picks the matched bin, and that bin's Lab value is written
+/// back into the float buffer, since Lab channels aren't byte-sized.
+fn match_lab_channel(src_plane: &mut [f32], ref_plane: &[f32], range: (f32, f32)) {
+    let src_cdf = equalize(cdf(lab_channel_histogram(src_plane, range)));
+    let ref_cdf = equalize(cdf(lab_channel_histogram(ref_plane, range)));
+    let map = mapping(&src_cdf, &ref_cdf);
+    for v in src_plane.iter_mut() {
+        *v = lab_unbin(map[lab_bin(*v, range)], range);
+    }
+}
+
+fn match_histogram_lab(source: &mut RgbImage, reference: &RgbImage, match_ab: bool) {
+    let (mut src_l, mut src_a, mut src_b): (Vec<f32>, Vec<f32>, Vec<f32>) = source
+        .pixels()
+        .map(|p| rgb_to_lab(p.0[0], p.0[1], p.0[2]))
+        .fold(
+            (Vec::new(), Vec::new(), Vec::new()),
+            |(mut ls, mut as_, mut bs), (l, a, b)| {
+                ls.push(l);
+                as_.push(a);
+                bs.push(b);
+                (ls, as_, bs)
+            },
+        );
+    let (ref_l, ref_a, ref_b): (Vec<f32>, Vec<f32>, Vec<f32>) = reference
+        .pixels()
+        .map(|p| rgb_to_lab(p.0[0], p.0[1], p.0[2]))
+        .fold(
+            (Vec::new(), Vec::new(), Vec::new()),
+            |(mut ls, mut as_, mut bs), (l, a, b)| {
+                ls.push(l);
+                as_.push(a);
+                bs.push(b);
+                (ls, as_, bs)
+            },
+        );
+
+    match_lab_channel(&mut src_l, &ref_l, L_RANGE);
+    if match_ab {
+        match_lab_channel(&mut src_a, &ref_a, AB_RANGE);
+        match_lab_channel(&mut src_b, &ref_b, AB_RANGE);
+    }
+
+    for (pixel, ((l, a), b)) in source
+        .pixels_mut()
+        .zip(src_l.iter().zip(src_a.iter()).zip(src_b.iter()))
+    {
+        pixel.0 = lab_to_rgb(*l, *a, *b);
+    }
+}
 
 pub struct ImageChannels<'image> {
     image: &'image mut [u8],
@@ -29,20 +356,76 @@ struct ChannelsHistogram {
     height: u32,
 }
 
+/// Rows per chunk when the histogram build is split across threads under
+/// the `parallel` feature. Picked to keep each chunk's work well above
+/// thread-spawn overhead without letting a single chunk dominate.
+#[cfg(feature = "parallel")]
+const PARALLEL_CHUNK_ROWS: u32 = 64;
+
+#[cfg(feature = "parallel")]
+fn add_histogram(mut a: [f32; 256], b: [f32; 256]) -> [f32; 256] {
+    for i in 0..256 {
+        a[i] += b[i];
+    }
+    a
+}
+
+fn rgb_histogram(image: &[u8]) -> ([f32; 256], [f32; 256], [f32; 256]) {
+    let mut histogram_r = [0.0; 256];
+    let mut histogram_g = [0.0; 256];
+    let mut histogram_b = [0.0; 256];
+    image.chunks_exact(3).for_each(|channel| {
+        histogram_r[channel[0] as usize] += 1.;
+        histogram_g[channel[1] as usize] += 1.;
+        histogram_b[channel[2] as usize] += 1.;
+    });
+    (histogram_r, histogram_g, histogram_b)
+}
+
+fn rgb_histogram_masked(image: &[u8], mask: &[bool]) -> ([f32; 256], [f32; 256], [f32; 256]) {
+    let mut histogram_r = [0.0; 256];
+    let mut histogram_g = [0.0; 256];
+    let mut histogram_b = [0.0; 256];
+    for (i, channel) in image.chunks_exact(3).enumerate() {
+        if mask.get(i).copied().unwrap_or(true) {
+            histogram_r[channel[0] as usize] += 1.;
+            histogram_g[channel[1] as usize] += 1.;
+            histogram_b[channel[2] as usize] += 1.;
+        }
+    }
+    (histogram_r, histogram_g, histogram_b)
+}
+
 impl From<&ImageChannels<'_>> for ChannelsHistogram {
     fn from(img: &ImageChannels) -> Self {
-        let mut histogram_r = [0.0; 256];
-        let mut histogram_g = [0.0; 256];
-        let mut histogram_b = [0.0; 256];
         let width = img.get_width();
         let height = img.get_height();
-        img.image.chunks_exact(3).for_each(|channel| {
-            histogram_r[channel[0] as usize] += 1.;
-            histogram_g[channel[1] as usize] += 1.;
-            histogram_b[channel[2] as usize] += 1.;
-        });
+
+        // Histogram construction is associative (each pixel contributes
+        // independently), so the buffer can be split into row chunks, built
+        // in parallel, and reduced with element-wise addition with no
+        // change in the result.
+        #[cfg(feature = "parallel")]
+        let (histogram_r, histogram_g, histogram_b) = img
+            .image
+            .par_chunks(PARALLEL_CHUNK_ROWS as usize * width as usize * 3)
+            .map(rgb_histogram)
+            .reduce(
+                || ([0.0; 256], [0.0; 256], [0.0; 256]),
+                |a, b| {
+                    (
+                        add_histogram(a.0, b.0),
+                        add_histogram(a.1, b.1),
+                        add_histogram(a.2, b.2),
+                    )
+                },
+            );
+
+        #[cfg(not(feature = "parallel"))]
+        let (histogram_r, histogram_g, histogram_b) = rgb_histogram(img.image);
+
         Self {
-            hist: (histogram_r, histogram_b, histogram_g),
+            hist: (histogram_r, histogram_g, histogram_b),
             width,
             height,
         }
@@ -58,13 +441,81 @@ impl ChannelsHistogram {
             _ => panic!("Only 'r'/'g'/'b' channel allowed."),
         }
     }
+
+    /// Same as the `From<&ImageChannels>` impl, but only pixels where `mask`
+    /// is `true` (or `mask` is absent) contribute to the histogram. Lets
+    /// callers match within a region instead of the whole frame. Gets the
+    /// same row-chunked parallel build as the unmasked path under the
+    /// `parallel` feature, since masking doesn't change the fact that each
+    /// pixel's contribution is independent.
+    fn from_masked(img: &ImageChannels, mask: Option<&[bool]>) -> Self {
+        let width = img.get_width();
+        let height = img.get_height();
+
+        #[cfg(feature = "parallel")]
+        let (histogram_r, histogram_g, histogram_b) = match mask {
+            None => img
+                .image
+                .par_chunks(PARALLEL_CHUNK_ROWS as usize * width as usize * 3)
+                .map(rgb_histogram)
+                .reduce(
+                    || ([0.0; 256], [0.0; 256], [0.0; 256]),
+                    |a, b| {
+                        (
+                            add_histogram(a.0, b.0),
+                            add_histogram(a.1, b.1),
+                            add_histogram(a.2, b.2),
+                        )
+                    },
+                ),
+            Some(mask) => {
+                // Pad/truncate to exactly one entry per pixel, defaulting
+                // missing entries to `true` like every other mask consumer
+                // in this file (`rgb_histogram_masked`, `apply_masked`,
+                // `apply_dithered`), so `par_chunks`'s `zip` can't silently
+                // drop pixels past a short mask the way it would if the two
+                // slices had different lengths.
+                let pixel_count = img.image.len() / 3;
+                let mask: Vec<bool> = (0..pixel_count)
+                    .map(|i| mask.get(i).copied().unwrap_or(true))
+                    .collect();
+                let chunk_pixels = (PARALLEL_CHUNK_ROWS as usize * width as usize).max(1);
+                img.image
+                    .par_chunks(chunk_pixels * 3)
+                    .zip(mask.par_chunks(chunk_pixels))
+                    .map(|(chunk, mask_chunk)| rgb_histogram_masked(chunk, mask_chunk))
+                    .reduce(
+                        || ([0.0; 256], [0.0; 256], [0.0; 256]),
+                        |a, b| {
+                            (
+                                add_histogram(a.0, b.0),
+                                add_histogram(a.1, b.1),
+                                add_histogram(a.2, b.2),
+                            )
+                        },
+                    )
+            }
+        };
+
+        #[cfg(not(feature = "parallel"))]
+        let (histogram_r, histogram_g, histogram_b) = match mask {
+            None => rgb_histogram(img.image),
+            Some(mask) => rgb_histogram_masked(img.image, mask),
+        };
+
+        Self {
+            hist: (histogram_r, histogram_g, histogram_b),
+            width,
+            height,
+        }
+    }
 }
 
 #[inline]
 fn equalize<const LEN: usize>(img: [f32; LEN]) -> [u32; LEN] {
     let mut new_pixel_level: [u32; LEN] = [0; LEN];
     for i in 0..LEN {
-        new_pixel_level[i] = ((img[i as usize] * 255.0).ceil()) as u32;
+        new_pixel_level[i] = ((img[i] * 255.0).ceil()) as u32;
     }
     new_pixel_level
 }
@@ -100,20 +551,28 @@ fn cdf<const LEN: usize>(arr: [f32; LEN]) -> [f32; LEN] {
 // or  mapping([(k1,v1); (k2, v2)], [(X1,Y1); (X2,Y2)]) => [(K1,X2), (k2,X1)]
 // These value were matched by looking at frequency.
 
-fn mapping<const LEN: usize>(src_img_cdf: &[u32; LEN], ref_img_cdf: &[u32; LEN]) -> [u8; 256] {
-    let lookup: BTreeMap<i64, i64> = ref_img_cdf
-        .iter()
-        .enumerate()
-        .map(|(value, frequency)| (*frequency as i64, value as i64))
-        .collect();
-    let mut mapped = [0; 256];
+fn mapping<const LEN: usize>(src_img_cdf: &[u32; LEN], ref_img_cdf: &[u32; LEN]) -> [u8; LEN] {
+    // Flat stretches of the CDF (runs of empty bins) share the same
+    // frequency, so several reference values collide on the same key here.
+    // Keep the smallest one (`or_insert`, not overwrite) so a lookup resolves
+    // to the start of that run, matching the usual CDF-inverse convention;
+    // otherwise an identical source/reference histogram wouldn't map to
+    // itself.
+    let mut lookup: BTreeMap<i64, i64> = BTreeMap::new();
+    for (value, frequency) in ref_img_cdf.iter().enumerate() {
+        lookup.entry(*frequency as i64).or_insert(value as i64);
+    }
+    let mut mapped = [0; LEN];
+    let last = LEN as i64 - 1;
     for (i, n) in src_img_cdf.iter().enumerate() {
         let key = *n as i64;
         let upper = lookup.range(key..).next();
-        let lower = lookup.range(..key).rev().next();
-        let upper = *upper.unwrap_or((&0, &255)).1;
-        let lower = *lower.unwrap_or((&0, &255)).1;
-        let ans = if (upper - key) <= (lower - key) {
+        let lower = lookup.range(..key).next_back();
+        // Compare CDF frequencies (the keys), not the values they map to; a
+        // missing neighbor gets a sentinel key far enough away to never win.
+        let (upper_key, upper) = upper.map_or((i64::MAX / 2, last), |(k, v)| (*k, *v));
+        let (lower_key, lower) = lower.map_or((i64::MIN / 2, last), |(k, v)| (*k, *v));
+        let ans = if (upper_key - key).abs() <= (lower_key - key).abs() {
             upper
         } else {
             lower
@@ -131,16 +590,127 @@ fn mapping<const LEN: usize>(src_img_cdf: &[u32; LEN], ref_img_cdf: &[u32; LEN])
 
 #[inline]
 fn apply(r_map: &[u8; 256], g_map: &[u8; 256], b_map: &[u8; 256], src_img: &mut [u8]) {
-    src_img.chunks_exact_mut(3).for_each(|channel| {
+    // Each pixel's substitution is independent, so under the `parallel`
+    // feature this splits across threads; the public API and the result
+    // are unchanged either way.
+    #[cfg(feature = "parallel")]
+    let iter = src_img.par_chunks_exact_mut(3);
+    #[cfg(not(feature = "parallel"))]
+    let iter = src_img.chunks_exact_mut(3);
+
+    iter.for_each(|channel| {
         channel[0] = r_map[channel[0] as usize];
         channel[1] = g_map[channel[1] as usize];
         channel[2] = b_map[channel[2] as usize];
     });
 }
 
+/// Same as [`apply`], but leaves pixels where `mask` is `false` untouched.
+fn apply_masked(
+    r_map: &[u8; 256],
+    g_map: &[u8; 256],
+    b_map: &[u8; 256],
+    src_img: &mut [u8],
+    mask: &[bool],
+) {
+    src_img
+        .chunks_exact_mut(3)
+        .enumerate()
+        .for_each(|(i, channel)| {
+            if mask.get(i).copied().unwrap_or(true) {
+                channel[0] = r_map[channel[0] as usize];
+                channel[1] = g_map[channel[1] as usize];
+                channel[2] = b_map[channel[2] as usize];
+            }
+        });
+}
+
+/// Same substitution as [`apply`], but diffuses each pixel's quantization
+/// error (`ideal - value_mapped`) to its neighbors with the Floyd-Steinberg
+/// 7/16, 3/16, 5/16, 1/16 kernel, serpentine-scanning rows to avoid
+/// directional artifacts. Needs `(x, y)` coordinates and a row-width error
+/// buffer rather than a flat `chunks_exact_mut(3)`.
+fn apply_dithered(
+    r_map: &[u8; 256],
+    g_map: &[u8; 256],
+    b_map: &[u8; 256],
+    src_img: &mut [u8],
+    width: u32,
+    mask: Option<&[bool]>,
+) {
+    let width = width as usize;
+    if width == 0 {
+        return;
+    }
+    let maps = [r_map, g_map, b_map];
+    // Padded by one slot on each side so the kernel can write to x-1/x+1
+    // without bounds checks.
+    let mut row_error = vec![[0.0f32; 3]; width + 2];
+    let mut next_row_error = vec![[0.0f32; 3]; width + 2];
+
+    for (y, row) in src_img.chunks_exact_mut(width * 3).enumerate() {
+        let forward: i32 = if y % 2 == 0 { 1 } else { -1 };
+        let xs: Box<dyn Iterator<Item = usize>> = if forward == 1 {
+            Box::new(0..width)
+        } else {
+            Box::new((0..width).rev())
+        };
+        for x in xs {
+            // Masked-out pixels are left untouched and don't diffuse error.
+            if !mask
+                .and_then(|m| m.get(y * width + x))
+                .copied()
+                .unwrap_or(true)
+            {
+                continue;
+            }
+            let ahead = (x as i32 + forward + 1) as usize;
+            let behind = (x as i32 - forward + 1) as usize;
+            for c in 0..3 {
+                let ideal = row[x * 3 + c] as f32 + row_error[x + 1][c];
+                let value = ideal.round().clamp(0.0, 255.0) as u8;
+                let mapped = maps[c][value as usize];
+                row[x * 3 + c] = mapped;
+
+                let error = ideal - mapped as f32;
+                row_error[ahead][c] += error * 7.0 / 16.0;
+                next_row_error[behind][c] += error * 3.0 / 16.0;
+                next_row_error[x + 1][c] += error * 5.0 / 16.0;
+                next_row_error[ahead][c] += error * 1.0 / 16.0;
+            }
+        }
+        std::mem::swap(&mut row_error, &mut next_row_error);
+        next_row_error.iter_mut().for_each(|e| *e = [0.0; 3]);
+    }
+}
+
 pub fn match_histogram_rgb_array(source: ImageChannels, reference: ImageChannels) {
+    match_histogram_rgb_array_full(source, reference, false, None);
+}
+
+/// Same as [`match_histogram_rgb_array`], with an optional Floyd-Steinberg
+/// dithering pass (see [`apply_dithered`]) in place of the cheap hard LUT
+/// substitution.
+pub fn match_histogram_rgb_array_dither(
+    source: ImageChannels,
+    reference: ImageChannels,
+    dither: bool,
+) {
+    match_histogram_rgb_array_full(source, reference, dither, None);
+}
+
+/// Full entry point: `mask`, if present, restricts histogram matching to
+/// source pixels where it is `true` (masked-out pixels don't contribute to
+/// `src_histo` and are left untouched by `apply`), and `dither` switches
+/// between the cheap hard LUT substitution and Floyd-Steinberg dithering.
+pub fn match_histogram_rgb_array_full(
+    source: ImageChannels,
+    reference: ImageChannels,
+    dither: bool,
+    mask: Option<&[bool]>,
+) {
     let ref_histo = ChannelsHistogram::from(&reference);
-    let src_histo = ChannelsHistogram::from(&source);
+    let src_histo = ChannelsHistogram::from_masked(&source, mask);
 
     let ref_cdf_r = equalize(cdf(ref_histo.get_channel('r')));
     let ref_cdf_g = equalize(cdf(ref_histo.get_channel('g')));
@@ -154,5 +724,664 @@ pub fn match_histogram_rgb_array(source: ImageChannels, reference: ImageChannels
     let mapped_g = mapping(&src_cdf_g, &ref_cdf_g);
     let mapped_b = mapping(&src_cdf_b, &ref_cdf_b);
 
-    apply(&mapped_r, &mapped_g, &mapped_b, source.image);
+    let width = source.get_width();
+    if dither {
+        apply_dithered(&mapped_r, &mapped_g, &mapped_b, source.image, width, mask);
+    } else if let Some(mask) = mask {
+        apply_masked(&mapped_r, &mapped_g, &mapped_b, source.image, mask);
+    } else {
+        apply(&mapped_r, &mapped_g, &mapped_b, source.image);
+    }
+}
+
+// --- Guided edge-preserving post-filter ---------------------------------
+//
+// Global matching bleeds tone across the whole frame. `weighted_median_filter`
+// smooths `apply`'s output while respecting edges, guided by the original
+// (un-matched) image.
+
+/// Number of value bins tracked per channel in the sliding-window histogram.
+const WMF_BINS: usize = 256;
+
+#[inline]
+fn wmf_pixel(channels: &ImageChannels, x: i32, y: i32) -> [u8; 3] {
+    let width = channels.get_width() as i32;
+    let height = channels.get_height() as i32;
+    let x = x.clamp(0, width - 1);
+    let y = y.clamp(0, height - 1);
+    let i = ((y * width + x) * 3) as usize;
+    [channels.image[i], channels.image[i + 1], channels.image[i + 2]]
+}
+
+#[inline]
+fn wmf_affinity(a: [u8; 3], b: [u8; 3], sigma: f32) -> f32 {
+    let dist_sq = color_distance_sq(a, b) as f32;
+    (-dist_sq / (2.0 * sigma * sigma)).exp()
+}
+
+type ChannelHistograms = [[f32; WMF_BINS]; 3];
+
+fn wmf_column(
+    image: &ImageChannels,
+    guide: &ImageChannels,
+    x: i32,
+    y: i32,
+    radius: i32,
+    sigma: f32,
+) -> ChannelHistograms {
+    let mut hist: ChannelHistograms = [[0.0; WMF_BINS]; 3];
+    let center = wmf_pixel(guide, x, y);
+    for dy in -radius..=radius {
+        let guide_px = wmf_pixel(guide, x, y + dy);
+        let image_px = wmf_pixel(image, x, y + dy);
+        let weight = wmf_affinity(guide_px, center, sigma);
+        for c in 0..3 {
+            hist[c][image_px[c] as usize] += weight;
+        }
+    }
+    hist
+}
+
+fn wmf_weighted_median(hist: &ChannelHistograms) -> [u8; 3] {
+    let mut out = [0u8; 3];
+    for (c, channel_hist) in hist.iter().enumerate() {
+        let total: f32 = channel_hist.iter().sum();
+        let half = total / 2.0;
+        let mut running = 0.0;
+        let mut value = 0u8;
+        for (v, &weight) in channel_hist.iter().enumerate() {
+            running += weight;
+            if running >= half {
+                value = v as u8;
+                break;
+            }
+        }
+        out[c] = value;
+    }
+    out
+}
+
+/// Weighted median filter over a `(2 * radius + 1)`-wide window, guided by
+/// `guide` (typically the image before histogram matching was applied).
+/// Each neighbor's weight is a Gaussian of its color distance to the window
+/// center in `guide`; the output is the value at which the running weight
+/// first reaches half the window's total weight, per channel.
+///
+/// Implemented with a joint (value bin, weight) histogram per window
+/// column, maintained in a sliding deque: moving one column to the right
+/// drops the outgoing column's histogram and adds the incoming one, so each
+/// step costs work proportional to the window height, not the whole frame.
+pub fn weighted_median_filter(
+    image: &ImageChannels,
+    guide: &ImageChannels,
+    radius: i32,
+    sigma: f32,
+) -> Vec<u8> {
+    let width = image.get_width() as i32;
+    let height = image.get_height() as i32;
+    let mut out = vec![0u8; image.image.len()];
+
+    for y in 0..height {
+        let mut columns: std::collections::VecDeque<ChannelHistograms> = (-radius..=radius)
+            .map(|dx| wmf_column(image, guide, dx, y, radius, sigma))
+            .collect();
+
+        let mut window: ChannelHistograms = [[0.0; WMF_BINS]; 3];
+        for column in &columns {
+            for c in 0..3 {
+                for b in 0..WMF_BINS {
+                    window[c][b] += column[c][b];
+                }
+            }
+        }
+
+        for x in 0..width {
+            let median = wmf_weighted_median(&window);
+            let i = ((y * width + x) * 3) as usize;
+            out[i] = median[0];
+            out[i + 1] = median[1];
+            out[i + 2] = median[2];
+
+            if x + 1 < width {
+                if let Some(outgoing) = columns.pop_front() {
+                    for c in 0..3 {
+                        for b in 0..WMF_BINS {
+                            window[c][b] -= outgoing[c][b];
+                        }
+                    }
+                }
+                let incoming = wmf_column(image, guide, x + radius + 1, y, radius, sigma);
+                for c in 0..3 {
+                    for b in 0..WMF_BINS {
+                        window[c][b] += incoming[c][b];
+                    }
+                }
+                columns.push_back(incoming);
+            }
+        }
+    }
+
+    out
+}
+
+// --- 16-bit / HDR support -----------------------------------------------
+//
+// The `[f32; 256]` histograms above assume 8-bit input and truncate anything
+// wider. Rather than grow that to `[f32; 65536]` (and still not generalize to
+// arbitrary bit depth), values are bucketed with a floor-of-log2 layout: a
+// leading-zero-derived exponent bucket plus a few mantissa sub-buckets, which
+// gives roughly constant relative error across the whole 16-bit range with
+// only `LOG_BINS` (65) bins per channel.
+
+/// Number of mantissa sub-buckets per exponent (as a power of two).
+const MANTISSA_BITS: u32 = 2;
+const MANTISSA_BUCKETS: u32 = 1 << MANTISSA_BITS;
+/// u16 covers exponents 0..=15, plus one bucket for the value zero.
+const LOG_BINS: usize = (16 * MANTISSA_BUCKETS) as usize + 1;
+
+/// Buckets `value` into one of `LOG_BINS` bins of fixed relative precision.
+#[inline]
+fn log_bucket(value: u16) -> usize {
+    if value == 0 {
+        return 0;
+    }
+    let exponent = 15 - value.leading_zeros();
+    let mantissa = if exponent >= MANTISSA_BITS {
+        (value >> (exponent - MANTISSA_BITS)) as u32 & (MANTISSA_BUCKETS - 1)
+    } else {
+        (value as u32) << (MANTISSA_BITS - exponent) & (MANTISSA_BUCKETS - 1)
+    };
+    1 + (exponent * MANTISSA_BUCKETS + mantissa) as usize
+}
+
+/// Inverse of [`log_bucket`]: a representative value for the bucket, used
+/// when mapping a matched bucket back to an actual pixel value.
+#[inline]
+fn log_unbucket(bucket: u16) -> u16 {
+    if bucket == 0 {
+        return 0;
+    }
+    let bucket = bucket as u32 - 1;
+    let exponent = bucket / MANTISSA_BUCKETS;
+    let mantissa = bucket % MANTISSA_BUCKETS;
+    let base = 1u32 << exponent;
+    let step = (base.max(1 << MANTISSA_BITS) >> MANTISSA_BITS).max(1);
+    (base + mantissa * step).min(u16::MAX as u32) as u16
+}
+
+#[allow(dead_code)]
+pub struct ImageChannels16<'image> {
+    image: &'image mut [u16],
+    width: u32,
+    height: u32,
+}
+
+impl<'image> ImageChannels16<'image> {
+    pub fn new(image: &'image mut [u16], width: u32, height: u32) -> Self {
+        Self {
+            image,
+            width,
+            height,
+        }
+    }
+}
+
+#[allow(dead_code)]
+struct LogHistogram {
+    hist: ([f32; LOG_BINS], [f32; LOG_BINS], [f32; LOG_BINS]),
+}
+
+impl From<&ImageChannels16<'_>> for LogHistogram {
+    fn from(img: &ImageChannels16) -> Self {
+        let mut histogram_r = [0.0; LOG_BINS];
+        let mut histogram_g = [0.0; LOG_BINS];
+        let mut histogram_b = [0.0; LOG_BINS];
+        img.image.chunks_exact(3).for_each(|channel| {
+            histogram_r[log_bucket(channel[0])] += 1.;
+            histogram_g[log_bucket(channel[1])] += 1.;
+            histogram_b[log_bucket(channel[2])] += 1.;
+        });
+        Self {
+            hist: (histogram_r, histogram_g, histogram_b),
+        }
+    }
+}
+
+/// Same idea as [`mapping`], generalized to the `LOG_BINS`-bucket histograms:
+/// walk the source CDF bucket by bucket and find the closest-frequency
+/// bucket in the reference CDF via the same `BTreeMap::range` lookup.
+fn mapping_buckets<const LEN: usize>(src_cdf: &[u32; LEN], ref_cdf: &[u32; LEN]) -> [u16; LEN] {
+    // See `mapping`'s comment: keep the smallest colliding bucket per
+    // frequency instead of overwriting it, so flat CDF runs resolve to the
+    // start of the run.
+    let mut lookup: BTreeMap<i64, i64> = BTreeMap::new();
+    for (bucket, frequency) in ref_cdf.iter().enumerate() {
+        lookup.entry(*frequency as i64).or_insert(bucket as i64);
+    }
+    let mut mapped = [0u16; LEN];
+    let last = LEN as i64 - 1;
+    for (i, n) in src_cdf.iter().enumerate() {
+        let key = *n as i64;
+        let upper = lookup.range(key..).next();
+        let lower = lookup.range(..key).next_back();
+        let (upper_key, upper) = upper.map_or((i64::MAX / 2, last), |(k, v)| (*k, *v));
+        let (lower_key, lower) = lower.map_or((i64::MIN / 2, last), |(k, v)| (*k, *v));
+        let ans = if (upper_key - key).abs() <= (lower_key - key).abs() {
+            upper
+        } else {
+            lower
+        };
+        mapped[i] = ans as u16;
+    }
+    mapped
+}
+
+fn apply_log(
+    r_map: &[u16; LOG_BINS],
+    g_map: &[u16; LOG_BINS],
+    b_map: &[u16; LOG_BINS],
+    src_img: &mut [u16],
+) {
+    src_img.chunks_exact_mut(3).for_each(|channel| {
+        channel[0] = log_unbucket(r_map[log_bucket(channel[0])]);
+        channel[1] = log_unbucket(g_map[log_bucket(channel[1])]);
+        channel[2] = log_unbucket(b_map[log_bucket(channel[2])]);
+    });
+}
+
+/// 16-bit counterpart to [`match_histogram_rgb_array`], for HDR/RAW content
+/// that would otherwise be truncated by the 8-bit, 256-bin pipeline.
+pub fn match_histogram_rgb_array_16(source: ImageChannels16, reference: ImageChannels16) {
+    let ref_histo = LogHistogram::from(&reference);
+    let src_histo = LogHistogram::from(&source);
+
+    let ref_cdf_r = equalize(cdf(ref_histo.hist.0));
+    let ref_cdf_g = equalize(cdf(ref_histo.hist.1));
+    let ref_cdf_b = equalize(cdf(ref_histo.hist.2));
+
+    let src_cdf_r = equalize(cdf(src_histo.hist.0));
+    let src_cdf_g = equalize(cdf(src_histo.hist.1));
+    let src_cdf_b = equalize(cdf(src_histo.hist.2));
+
+    let mapped_r = mapping_buckets(&src_cdf_r, &ref_cdf_r);
+    let mapped_g = mapping_buckets(&src_cdf_g, &ref_cdf_g);
+    let mapped_b = mapping_buckets(&src_cdf_b, &ref_cdf_b);
+
+    apply_log(&mapped_r, &mapped_g, &mapped_b, source.image);
+}
+
+// --- Palette reduction / color quantization -----------------------------
+//
+// Reduces an (optionally histogram-matched) image down to a fixed-size
+// palette, for formats like GIF or indexed PNG that need one. Takes the
+// same `ImageChannels` input as `ChannelsHistogram`, but makes its own pass
+// over it, counting joint (r, g, b) colors instead of per-channel marginals;
+// `ChannelsHistogram`'s counts are not reused.
+
+fn unique_color_counts(image: &ImageChannels) -> Vec<([u8; 3], u32)> {
+    let mut counts: HashMap<[u8; 3], u32> = HashMap::new();
+    image.image.chunks_exact(3).for_each(|channel| {
+        *counts.entry([channel[0], channel[1], channel[2]]).or_insert(0) += 1;
+    });
+    counts.into_iter().collect()
+}
+
+#[inline]
+fn color_distance_sq(a: [u8; 3], b: [u8; 3]) -> u32 {
+    (0..3)
+        .map(|c| {
+            let d = a[c] as i32 - b[c] as i32;
+            (d * d) as u32
+        })
+        .sum()
+}
+
+fn nearest_palette_entry(color: [u8; 3], palette: &[[u8; 3]]) -> usize {
+    palette
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, entry)| color_distance_sq(color, **entry))
+        .map(|(index, _)| index)
+        .unwrap()
+}
+
+/// A box in the median-cut color cube: the (weighted) colors that fall
+/// inside it.
+struct ColorBox {
+    colors: Vec<([u8; 3], u32)>,
+}
+
+impl ColorBox {
+    fn channel_range(&self, channel: usize) -> (u8, u8) {
+        let mut min = u8::MAX;
+        let mut max = u8::MIN;
+        for (color, _) in &self.colors {
+            min = min.min(color[channel]);
+            max = max.max(color[channel]);
+        }
+        (min, max)
+    }
+
+    fn longest_axis(&self) -> usize {
+        (0..3)
+            .max_by_key(|&channel| {
+                let (min, max) = self.channel_range(channel);
+                max - min
+            })
+            .unwrap()
+    }
+
+    fn population(&self) -> u32 {
+        self.colors.iter().map(|(_, count)| count).sum()
+    }
+
+    fn average(&self) -> [u8; 3] {
+        let mut sum = [0u64; 3];
+        let mut total = 0u64;
+        for (color, count) in &self.colors {
+            for c in 0..3 {
+                sum[c] += color[c] as u64 * *count as u64;
+            }
+            total += *count as u64;
+        }
+        if total == 0 {
+            return [0; 3];
+        }
+        [
+            (sum[0] / total) as u8,
+            (sum[1] / total) as u8,
+            (sum[2] / total) as u8,
+        ]
+    }
+}
+
+/// Recursively splits `colors` into at most `max_colors` boxes, each time
+/// cutting the box with the widest channel range along its longest axis at
+/// the (population-weighted) median, then averages each box down to a
+/// single palette entry.
+fn median_cut(colors: Vec<([u8; 3], u32)>, max_colors: usize) -> Vec<[u8; 3]> {
+    if colors.is_empty() || max_colors == 0 {
+        return Vec::new();
+    }
+    let mut boxes = vec![ColorBox { colors }];
+
+    while boxes.len() < max_colors {
+        let Some((index, _)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.colors.len() > 1)
+            .max_by_key(|(_, b)| {
+                let axis = b.longest_axis();
+                let (min, max) = b.channel_range(axis);
+                max - min
+            })
+        else {
+            break;
+        };
+
+        let mut to_split = boxes.swap_remove(index);
+        let axis = to_split.longest_axis();
+        to_split.colors.sort_by_key(|(color, _)| color[axis]);
+
+        let half_population = to_split.population() / 2;
+        let mut running = 0u32;
+        let mut split_at = to_split.colors.len() / 2;
+        for (i, (_, count)) in to_split.colors.iter().enumerate() {
+            running += count;
+            if running >= half_population {
+                split_at = (i + 1).clamp(1, to_split.colors.len() - 1);
+                break;
+            }
+        }
+
+        let second_half = to_split.colors.split_off(split_at);
+        boxes.push(to_split);
+        boxes.push(ColorBox {
+            colors: second_half,
+        });
+    }
+
+    boxes.iter().map(ColorBox::average).collect()
+}
+
+/// Refines `palette` with LBG/k-means: assign each unique color (weighted by
+/// its histogram count) to the nearest palette entry, recompute each entry
+/// as the count-weighted mean of its assigned colors, and repeat. Entries
+/// that end up with no assigned colors are moved to the worst-fitting color
+/// of the highest-distortion cluster instead of sitting dead, so the
+/// refinement doesn't stall in a bad local minimum.
+fn lbg_refine(
+    colors: &[([u8; 3], u32)],
+    mut palette: Vec<[u8; 3]>,
+    iterations: usize,
+) -> Vec<[u8; 3]> {
+    if palette.is_empty() {
+        return palette;
+    }
+    for _ in 0..iterations {
+        let mut sums = vec![[0u64; 3]; palette.len()];
+        let mut counts = vec![0u64; palette.len()];
+        let mut distortion = vec![0u64; palette.len()];
+        // Per entry, the assigned color farthest from it (the entry itself
+        // while nothing is farther).
+        let mut farthest: Vec<(u32, [u8; 3])> = palette.iter().map(|&p| (0, p)).collect();
+
+        for &(color, count) in colors {
+            let index = nearest_palette_entry(color, &palette);
+            for c in 0..3 {
+                sums[index][c] += color[c] as u64 * count as u64;
+            }
+            counts[index] += count as u64;
+            let dist = color_distance_sq(color, palette[index]);
+            distortion[index] += dist as u64 * count as u64;
+            if dist > farthest[index].0 {
+                farthest[index] = (dist, color);
+            }
+        }
+
+        let Some((worst, _)) = distortion.iter().enumerate().max_by_key(|(_, d)| **d) else {
+            break;
+        };
+
+        for i in 0..palette.len() {
+            let count = counts[i];
+            palette[i] = [0, 1, 2].map(|c| {
+                sums[i][c]
+                    .checked_div(count)
+                    .map(|v| v as u8)
+                    .unwrap_or(farthest[worst].1[c])
+            });
+        }
+    }
+    palette
+}
+
+/// Number of LBG refinement passes run after the initial median cut.
+const LBG_ITERATIONS: usize = 8;
+
+/// Reduces `image` to at most `max_colors` colors: a palette built with
+/// median cut and refined with LBG/k-means, plus each pixel's index into
+/// that palette.
+pub fn quantize(image: &ImageChannels, max_colors: usize) -> (Vec<[u8; 3]>, Vec<u8>) {
+    let colors = unique_color_counts(image);
+    let palette = median_cut(colors.clone(), max_colors);
+    let palette = lbg_refine(&colors, palette, LBG_ITERATIONS);
+
+    // An empty palette (max_colors == 0) has no entry for
+    // `nearest_palette_entry` to return, so every pixel maps to index 0
+    // rather than looking one up.
+    if palette.is_empty() {
+        let pixel_count = image.image.len() / 3;
+        return (palette, vec![0; pixel_count]);
+    }
+
+    let indices = image
+        .image
+        .chunks_exact(3)
+        .map(|channel| nearest_palette_entry([channel[0], channel[1], channel[2]], &palette) as u8)
+        .collect();
+
+    (palette, indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_bucket_round_trips_within_relative_tolerance() {
+        for value in [1u16, 2, 7, 255, 256, 1000, 32767, 65535] {
+            let bucket = log_bucket(value);
+            let approx = log_unbucket(bucket as u16);
+            let relative_error = (approx as f32 - value as f32).abs() / value as f32;
+            assert!(
+                relative_error < 0.25,
+                "value {value} bucketed to {bucket} and back to {approx}, relative error {relative_error}"
+            );
+        }
+        assert_eq!(log_bucket(0), 0);
+        assert_eq!(log_unbucket(0), 0);
+    }
+
+    #[test]
+    fn mapping_picks_the_truly_nearest_cdf_value() {
+        // ref_cdf holds 0, 10, 20, 30, ... at indices 0, 1, 2, 3, ...
+        // src_cdf[0] = 26 sits between ref indices 2 (20, distance 6) and
+        // 3 (30, distance 4), so the nearest match is index 3 — a
+        // same-sign-distance bug that drops the `.abs()` would instead
+        // always prefer the lower index here.
+        let mut ref_cdf = [0u32; 256];
+        for (i, v) in ref_cdf.iter_mut().enumerate() {
+            *v = i as u32 * 10;
+        }
+        let mut src_cdf = [0u32; 256];
+        src_cdf[0] = 26;
+
+        let mapped = mapping(&src_cdf, &ref_cdf);
+        assert_eq!(mapped[0], 3);
+    }
+
+    #[test]
+    fn quantize_handles_max_colors_zero_one_and_many() {
+        let mut pixels = vec![
+            10, 10, 10, 200, 200, 200, 10, 10, 10, 50, 60, 70,
+        ];
+        let width = 4;
+        let height = 1;
+
+        {
+            let channels = ImageChannels::new(&mut pixels, width, height);
+            let (palette, indices) = quantize(&channels, 0);
+            assert!(palette.is_empty());
+            assert_eq!(indices, vec![0; 4]);
+        }
+        {
+            let channels = ImageChannels::new(&mut pixels, width, height);
+            let (palette, indices) = quantize(&channels, 1);
+            assert_eq!(palette.len(), 1);
+            assert!(indices.iter().all(|&i| i == 0));
+        }
+        {
+            let channels = ImageChannels::new(&mut pixels, width, height);
+            let (palette, indices) = quantize(&channels, 8);
+            assert!(palette.len() <= 8);
+            assert_eq!(indices.len(), 4);
+        }
+    }
+
+    #[test]
+    fn matching_an_image_against_itself_is_near_identity() {
+        let mut source = vec![
+            10, 20, 30, 200, 150, 100, 10, 20, 30, 200, 150, 100,
+        ];
+        let expected = source.clone();
+        let mut reference = source.clone();
+        let width = 2;
+        let height = 2;
+
+        let src_channels = ImageChannels::new(&mut source, width, height);
+        let ref_channels = ImageChannels::new(&mut reference, width, height);
+        match_histogram_rgb_array(src_channels, ref_channels);
+
+        assert_eq!(source, expected);
+    }
+
+    #[test]
+    fn rgb_to_lab_to_rgb_round_trips_within_rounding_error() {
+        for color in [
+            [0, 0, 0],
+            [255, 255, 255],
+            [255, 0, 0],
+            [0, 255, 0],
+            [0, 0, 255],
+            [128, 64, 200],
+        ] {
+            let (l, a, b) = rgb_to_lab(color[0], color[1], color[2]);
+            let round_tripped = lab_to_rgb(l, a, b);
+            for i in 0..3 {
+                let diff = (round_tripped[i] as i16 - color[i] as i16).abs();
+                assert!(
+                    diff <= 1,
+                    "channel {i} of {color:?} round-tripped through Lab to {round_tripped:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn masked_match_leaves_pixels_outside_the_mask_untouched() {
+        let mut source = vec![
+            10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10,
+        ];
+        let mut reference = vec![
+            200, 200, 200, 200, 200, 200, 200, 200, 200, 200, 200, 200,
+        ];
+        let width = 2;
+        let height = 2;
+        // Only the first pixel contributes to / receives the match; the
+        // other three should come out of `match_histogram_rgb_array_full`
+        // exactly as they went in.
+        let mask = vec![true, false, false, false];
+
+        let src_channels = ImageChannels::new(&mut source, width, height);
+        let ref_channels = ImageChannels::new(&mut reference, width, height);
+        match_histogram_rgb_array_full(src_channels, ref_channels, false, Some(&mask));
+
+        assert_eq!(&source[0..3], &[200, 200, 200]);
+        assert_eq!(&source[3..12], &[10, 10, 10, 10, 10, 10, 10, 10, 10]);
+    }
+
+    #[test]
+    fn dithering_perturbs_pixels_that_the_hard_lut_would_leave_banded() {
+        // A smooth 8-step ramp matched against a reference with only two
+        // populated levels collapses the hard LUT onto just those two
+        // values (banding); dithering should diffuse the quantization
+        // error instead of reproducing the same banded output.
+        let ramp: Vec<u8> = (0..8).map(|i| i * 30).collect();
+        let mut source: Vec<u8> = ramp.iter().flat_map(|&v| [v, v, v]).collect();
+        let mut reference = vec![0u8; 24];
+        reference[12..].iter_mut().for_each(|v| *v = 255);
+        let width = 8;
+        let height = 1;
+
+        let mut hard = source.clone();
+        let mut hard_reference = reference.clone();
+        let hard_channels = ImageChannels::new(&mut hard, width, height);
+        let ref_channels = ImageChannels::new(&mut hard_reference, width, height);
+        match_histogram_rgb_array_full(hard_channels, ref_channels, false, None);
+
+        let dithered_channels = ImageChannels::new(&mut source, width, height);
+        let ref_channels = ImageChannels::new(&mut reference, width, height);
+        match_histogram_rgb_array_full(dithered_channels, ref_channels, true, None);
+
+        // The hard LUT has only two possible outputs (0 and 255), so it
+        // snaps the ramp to a sharp, solid-block threshold.
+        assert_eq!(hard.iter().collect::<std::collections::HashSet<_>>().len(), 2);
+        // Dithering diffuses the rounding error from each choice into its
+        // neighbors, so it shouldn't land on that same sharp threshold.
+        assert_ne!(
+            source, hard,
+            "dithered output should diffuse error instead of reproducing the hard LUT's banding"
+        );
+    }
 }